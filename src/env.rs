@@ -1,3 +1,4 @@
+use chrono::Duration;
 use std::net::SocketAddr;
 
 #[derive(Debug)]
@@ -29,11 +30,26 @@ pub fn get_conf<'a>() -> Result<EnvConfiguration, EnvError<'a>> {
         var("ICAL_FILTER_SOCKETADDR"),
         "127.0.0.1:8080".parse().unwrap(),
     )?;
+    let rrule_lookback = Duration::days(with_default(var("ICAL_FILTER_RRULE_LOOKBACK"), 30)?);
+    let rrule_lookahead = Duration::days(with_default(var("ICAL_FILTER_RRULE_LOOKAHEAD"), 366)?);
+    let cache_max_age = Duration::seconds(with_default(var("ICAL_FILTER_CACHE_MAX_AGE"), 300)?);
 
-    Ok(EnvConfiguration { socketaddr })
+    Ok(EnvConfiguration {
+        socketaddr,
+        rrule_lookback,
+        rrule_lookahead,
+        cache_max_age,
+    })
 }
 
 #[derive(Clone)]
 pub struct EnvConfiguration {
     pub socketaddr: SocketAddr,
+    /// How far before `now` a recurring event's occurrences are still expanded.
+    pub rrule_lookback: Duration,
+    /// How far beyond `now` a recurring event's occurrences are still expanded.
+    pub rrule_lookahead: Duration,
+    /// How long a cached upstream feed is served without revalidation when the
+    /// upstream sent neither an `ETag` nor a `Last-Modified` header.
+    pub cache_max_age: Duration,
 }
@@ -1,11 +1,13 @@
 use regex::Regex;
 use serde::de;
+use std::collections::HashMap;
 use std::fmt;
 
 // this lint is falsely triggering on this, which is *not* interior mutable
 #[allow(clippy::declare_interior_mutable_const)]
 pub const TRUE_FILTER: Filter = Filter {
     invert: false,
+    field: None,
     operator: FilterOperator::True,
 };
 
@@ -14,6 +16,7 @@ pub enum FilterErrorKind {
     MissingColon,
     OperatorParse,
     RegexError(regex::Error),
+    UnbalancedGroup,
 }
 
 #[derive(Debug)]
@@ -27,14 +30,55 @@ enum FilterOperator {
     Contains(String),
     True,
     Regex(Regex),
+    All(Vec<Filter>),
+    Any(Vec<Filter>),
 }
 
 #[derive(Debug)]
 pub struct Filter {
     invert: bool,
+    /// Property this filter matches against; `None` means "whatever property
+    /// the request's `field` parameter selected".
+    field: Option<String>,
     operator: FilterOperator,
 }
 
+/// Splits `and:`/`or:` content into its parenthesized sub-filters, e.g.
+/// `(equals:A)(startsWith:B)` into `["equals:A", "startsWith:B"]`. A literal
+/// paren inside a sub-filter is written as `\(`/`\)`.
+fn parse_groups(s: &str) -> Result<Vec<String>, FilterError> {
+    let mut groups = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '(' {
+            return Err(FilterError(s.to_owned(), FilterErrorKind::UnbalancedGroup));
+        }
+
+        let mut depth = 1;
+        let mut current = String::new();
+        loop {
+            match chars.next() {
+                Some('\\') => current.extend(chars.next()),
+                Some('(') => {
+                    depth += 1;
+                    current.push('(');
+                }
+                Some(')') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                    current.push(')');
+                }
+                Some(other) => current.push(other),
+                None => return Err(FilterError(s.to_owned(), FilterErrorKind::UnbalancedGroup)),
+            }
+        }
+        groups.push(current);
+    }
+    Ok(groups)
+}
+
 impl FilterOperator {
     /// Parses a stringified filter operator into a [`FilterOperator`](enum.FilterOperator.html)
     fn parse(s: &str, content: String) -> Result<FilterOperator, FilterError> {
@@ -45,12 +89,25 @@ impl FilterOperator {
             "contains" => Ok(FilterOperator::Contains(content)),
             "true" => Ok(FilterOperator::True),
             "regex" => Ok(FilterOperator::Regex(Regex::new(&content).map_err(|e| FilterError(content, FilterErrorKind::RegexError(e)))?)),
-            _ => Err(FilterError("unknown filter operator; options are equals, startsWith, endsWith, contains, true, regex".to_owned(), FilterErrorKind::OperatorParse)),
+            "and" => Ok(FilterOperator::All(
+                parse_groups(&content)?
+                    .iter()
+                    .map(|g| Filter::parse(g))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )),
+            "or" => Ok(FilterOperator::Any(
+                parse_groups(&content)?
+                    .iter()
+                    .map(|g| Filter::parse(g))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )),
+            _ => Err(FilterError("unknown filter operator; options are equals, startsWith, endsWith, contains, true, regex, and, or".to_owned(), FilterErrorKind::OperatorParse)),
         }
     }
 
-    /// Execute the filter operator on the given string. Returns whether it
-    /// matches.
+    /// Execute a leaf filter operator on the given string. Returns whether it
+    /// matches. `All`/`Any` are evaluated by [`Filter::matches`] instead, since
+    /// they recurse over child filters rather than a single string.
     fn matches(&self, s: &str) -> bool {
         match self {
             FilterOperator::Equals(pat) => s == pat,
@@ -59,6 +116,9 @@ impl FilterOperator {
             FilterOperator::Contains(pat) => s.contains(pat),
             FilterOperator::True => true,
             FilterOperator::Regex(pat) => pat.is_match(s),
+            FilterOperator::All(_) | FilterOperator::Any(_) => {
+                unreachable!("All/Any are dispatched in Filter::matches")
+            }
         }
     }
 }
@@ -66,6 +126,18 @@ impl FilterOperator {
 impl Filter {
     /// Parses a filter into a Filter struct.
     fn parse(s: &str) -> Result<Filter, FilterError> {
+        let (field, s) = match s.find('=') {
+            Some(eq)
+                if s[..eq]
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                    && s.find(':').map_or(true, |colon| eq < colon) =>
+            {
+                (Some(s[..eq].to_owned()), &s[eq + 1..])
+            }
+            _ => (None, s),
+        };
+
         let invert = s.starts_with('!');
         let s = if invert { &s[1..] } else { s };
 
@@ -76,12 +148,29 @@ impl Filter {
         // chop off colon
         let text = &text[1..];
         let operator = FilterOperator::parse(operator, text.to_owned())?;
-        Ok(Filter { invert, operator })
+        Ok(Filter {
+            invert,
+            field,
+            operator,
+        })
     }
 
-    /// Execute the filter on the given string. Returns whether it matches.
-    pub fn matches(&self, s: &str) -> bool {
-        let result = self.operator.matches(s);
+    /// Execute the filter against the given property map. `default_field` is
+    /// the property to match a leaf filter against when it doesn't name its
+    /// own via a `FIELD=` prefix. Returns whether it matches.
+    pub fn matches(&self, default_field: &str, properties: &HashMap<String, String>) -> bool {
+        let result = match &self.operator {
+            FilterOperator::All(children) => {
+                children.iter().all(|f| f.matches(default_field, properties))
+            }
+            FilterOperator::Any(children) => {
+                children.iter().any(|f| f.matches(default_field, properties))
+            }
+            leaf => {
+                let field = self.field.as_deref().unwrap_or(default_field);
+                properties.get(field).map_or(false, |v| leaf.matches(v))
+            }
+        };
         if self.invert {
             !result
         } else {
@@ -101,7 +190,7 @@ impl<'de> de::Deserialize<'de> for Filter {
             type Value = Filter;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("[!]operator:filter")
+                formatter.write_str("[FIELD=][!]operator:filter")
             }
 
             fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
@@ -120,6 +209,13 @@ impl<'de> de::Deserialize<'de> for Filter {
 mod test {
     use super::*;
 
+    fn props(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
     #[test]
     fn test_load_filter() {
         let qs = "startsWith:aaa";
@@ -127,19 +223,34 @@ mod test {
         assert_eq!(parsed.invert, false);
         assert!(matches!(
             parsed.operator,
-            FilterOperator::StartsWith(a) if a == "aaa"
+            FilterOperator::StartsWith(ref a) if a == "aaa"
         ));
 
         let qs = "true:aaa";
         let parsed = Filter::parse(qs).unwrap();
         assert_eq!(parsed.invert, false);
         assert!(matches!(parsed.operator, FilterOperator::True));
-        assert!(parsed.matches("a"));
+        assert!(parsed.matches("SUMMARY", &props(&[("SUMMARY", "a")])));
 
         let qs = "!true:aaa";
         let parsed = Filter::parse(qs).unwrap();
         assert_eq!(parsed.invert, true);
         assert!(matches!(parsed.operator, FilterOperator::True));
-        assert!(!parsed.matches("a"));
+        assert!(!parsed.matches("SUMMARY", &props(&[("SUMMARY", "a")])));
+    }
+
+    #[test]
+    fn test_compound_filter() {
+        let qs = "and:(contains:Standup)(LOCATION=startsWith:Room)";
+        let parsed = Filter::parse(qs).unwrap();
+        let matching = props(&[("SUMMARY", "Standup sync"), ("LOCATION", "Room 3")]);
+        let non_matching = props(&[("SUMMARY", "Standup sync"), ("LOCATION", "Kitchen")]);
+        assert!(parsed.matches("SUMMARY", &matching));
+        assert!(!parsed.matches("SUMMARY", &non_matching));
+
+        let qs = "or:(equals:Standup)(equals:Retro)";
+        let parsed = Filter::parse(qs).unwrap();
+        assert!(parsed.matches("SUMMARY", &props(&[("SUMMARY", "Retro")])));
+        assert!(!parsed.matches("SUMMARY", &props(&[("SUMMARY", "1:1")])));
     }
 }
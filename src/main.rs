@@ -1,18 +1,19 @@
 //! HTTP server that fetches an ical feed and normalizes + filters it.
 
 use crate::env::EnvConfiguration;
-use crate::error::Result;
-use actix_web::{
-    middleware::Logger,
-    web::{self, Query},
-    App, HttpResponse, HttpServer,
-};
+use crate::error::{Error, Result};
+use crate::filter::Filter;
+use actix_web::{middleware::Logger, web, App, HttpRequest, HttpResponse, HttpServer};
 use chrono::{DateTime, Utc};
 use chrono_tz::{Tz, UTC};
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 
 pub mod env;
 pub mod error;
+pub mod filter;
+pub mod recurrence;
 pub mod upstream;
 
 #[derive(Serialize)]
@@ -23,130 +24,433 @@ pub struct Event {
     created: Option<DateTime<Utc>>,
     start: Option<DateTime<Utc>>,
     end: Option<DateTime<Utc>>,
+    /// Whether `start`/`end` are midnight-anchored calendar dates (`DTSTART;VALUE=DATE`)
+    /// rather than precise instants.
+    all_day: bool,
+    description: Option<String>,
+    location: Option<String>,
+    url: Option<String>,
 }
 
 fn instant_to_icalstr(t: &DateTime<Utc>) -> String {
     t.format("%Y%m%dT%H%M%SZ").to_string()
 }
 
+fn instant_to_icaldatestr(t: &DateTime<Utc>) -> String {
+    t.format("%Y%m%d").to_string()
+}
+
 impl std::convert::From<Event> for ics::Event<'_> {
     fn from(e: Event) -> Self {
         let mut res = ics::Event::new(e.uid, instant_to_icalstr(&e.stamp));
 
         use ics::properties::*;
         res.push(Summary::new(e.summary));
-        if let Some(start) = e.start.as_ref() {
-            res.push(DtStart::new(instant_to_icalstr(&start)));
-        }
-        if let Some(end) = e.start.as_ref() {
-            res.push(DtEnd::new(instant_to_icalstr(&end)));
+        if e.all_day {
+            if let Some(start) = e.start.as_ref() {
+                let mut prop = DtStart::new(instant_to_icaldatestr(start));
+                prop.add(("VALUE", "DATE"));
+                res.push(prop);
+            }
+            if let Some(end) = e.end.as_ref() {
+                let mut prop = DtEnd::new(instant_to_icaldatestr(end));
+                prop.add(("VALUE", "DATE"));
+                res.push(prop);
+            }
+        } else {
+            if let Some(start) = e.start.as_ref() {
+                res.push(DtStart::new(instant_to_icalstr(start)));
+            }
+            if let Some(end) = e.end.as_ref() {
+                res.push(DtEnd::new(instant_to_icalstr(end)));
+            }
         }
         if let Some(created) = e.created.as_ref() {
             res.push(Created::new(instant_to_icalstr(created)));
         }
+        if let Some(description) = e.description {
+            res.push(Description::new(description));
+        }
+        if let Some(location) = e.location {
+            res.push(Location::new(location));
+        }
+        if let Some(url) = e.url {
+            res.push(Url::new(url));
+        }
 
         res
     }
 }
 
+/// A single parsed VEVENT, before recurrence expansion and series/override merging.
+struct RawEvent {
+    uid: String,
+    summary: String,
+    stamp: DateTime<Utc>,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    created: Option<DateTime<Utc>>,
+    /// Every property seen on this VEVENT, by name, for compound filter matching.
+    properties: HashMap<String, String>,
+    rrule: Option<String>,
+    /// The `TZID` `DTSTART` was expressed in, so recurrence expansion can stay
+    /// anchored to the event's local wall-clock time across DST transitions.
+    dtstart_tz: Tz,
+    exdates: Vec<DateTime<Utc>>,
+    rdates: Vec<DateTime<Utc>>,
+    /// Set when this VEVENT is a `RECURRENCE-ID` override of a single occurrence
+    /// of another event's series.
+    recurrence_id: Option<DateTime<Utc>>,
+    all_day: bool,
+    description: Option<String>,
+    location: Option<String>,
+    url: Option<String>,
+}
+
+impl RawEvent {
+    fn matches(&self, default_field: &str, filters: &[Filter]) -> bool {
+        filters
+            .iter()
+            .all(|f| f.matches(default_field, &self.properties))
+    }
+
+    fn to_event(&self) -> Event {
+        Event {
+            uid: self.uid.clone(),
+            summary: self.summary.clone(),
+            stamp: self.stamp,
+            start: self.start,
+            end: self.end,
+            created: self.created,
+            all_day: self.all_day,
+            description: self.description.clone(),
+            location: self.location.clone(),
+            url: self.url.clone(),
+        }
+    }
+}
+
+fn parse_vevent(e: ical::parser::ical::component::IcalEvent) -> Result<Option<RawEvent>> {
+    let mut uid = None;
+    let mut summary = None;
+    let mut stamp = None;
+    let mut start = None;
+    let mut end = None;
+    let mut created = None;
+    let mut properties = HashMap::new();
+    let mut rrule = None;
+    let mut dtstart_tz = UTC;
+    let mut exdates = Vec::new();
+    let mut rdates = Vec::new();
+    let mut recurrence_id = None;
+    let mut all_day = false;
+    let mut description = None;
+    let mut location = None;
+    let mut url = None;
+    for p in e.properties.into_iter() {
+        let mut tz = UTC;
+        let mut is_date = false;
+        if let Some(params) = p.params {
+            for (name, values) in params {
+                if name == "TZID" && values.len() == 1 {
+                    use std::str::FromStr;
+                    if let Ok(current_tz) = Tz::from_str(&values[0]) {
+                        tz = current_tz;
+                    }
+                }
+                if name == "VALUE" && values.iter().any(|v| v == "DATE") {
+                    is_date = true;
+                }
+            }
+        }
+
+        let datetime_for_str = |s: String| -> Result<DateTime<Utc>> {
+            use chrono::offset::TimeZone;
+            if is_date || (s.len() == 8 && s.bytes().all(|b| b.is_ascii_digit())) {
+                let dt = tz.datetime_from_str(&format!("{}T000000", s), "%Y%m%dT%H%M%S")?;
+                return Ok(dt.with_timezone(&Utc));
+            }
+            let dt = tz.datetime_from_str(&s, "%Y%m%dT%H%M%S");
+            if let Ok(dt) = dt {
+                Ok(dt.with_timezone(&Utc))
+            } else {
+                Ok(Utc.datetime_from_str(&s, "%Y%m%dT%H%M%SZ")?)
+            }
+        };
+
+        if let Some(v) = &p.value {
+            properties.insert(p.name.clone(), v.clone());
+        }
+
+        match p.name.as_str() {
+            "UID" => uid = p.value,
+            "SUMMARY" => summary = p.value,
+            "DTSTAMP" => stamp = p.value.map(datetime_for_str),
+            "DTSTART" => {
+                if is_date {
+                    all_day = true;
+                }
+                dtstart_tz = tz;
+                start = p.value.map(datetime_for_str);
+            }
+            "DTEND" => end = p.value.map(datetime_for_str),
+            "CREATED" => created = p.value.map(datetime_for_str),
+            "DESCRIPTION" => description = p.value,
+            "LOCATION" => location = p.value,
+            "URL" => url = p.value,
+            "RRULE" => rrule = p.value,
+            "EXDATE" => {
+                if let Some(v) = p.value {
+                    for part in v.split(',') {
+                        exdates.push(datetime_for_str(part.to_owned())?);
+                    }
+                }
+            }
+            "RDATE" => {
+                if let Some(v) = p.value {
+                    for part in v.split(',') {
+                        rdates.push(datetime_for_str(part.to_owned())?);
+                    }
+                }
+            }
+            "RECURRENCE-ID" => recurrence_id = p.value.map(datetime_for_str).transpose()?,
+            _ => (),
+        }
+    }
+
+    Ok(match (uid, summary, stamp) {
+        (Some(uid), Some(summary), Some(stamp)) => Some(RawEvent {
+            uid,
+            summary,
+            stamp: stamp?,
+            start: start.transpose()?,
+            end: end.transpose()?,
+            created: created.transpose()?,
+            properties,
+            rrule,
+            dtstart_tz,
+            exdates,
+            rdates,
+            recurrence_id,
+            all_day,
+            description,
+            location,
+            url,
+        }),
+        _ => None,
+    })
+}
+
+/// Whether an event (treated as `[start, end)`) overlaps the requested CalDAV-style
+/// time-range. `None` means no time-range was requested, so everything passes.
+fn in_time_range(event: &Event, range: Option<(DateTime<Utc>, DateTime<Utc>)>) -> bool {
+    match range {
+        None => true,
+        Some((range_start, range_end)) => match event.start {
+            Some(start) => {
+                let end = event.end.unwrap_or(start);
+                start < range_end && end > range_start
+            }
+            None => false,
+        },
+    }
+}
+
 async fn compute_events<'a>(
     url: &str,
-    selector: &'a str,
-) -> Result<impl Iterator<Item = Result<impl Iterator<Item = Result<Event>> + 'a>>> {
-    let calendars = upstream::get_calendars(url).await?;
+    field: &'a str,
+    filters: &'a [Filter],
+    conf: &'a Conf,
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+) -> Result<impl Iterator<Item = Result<Vec<Event>>> + 'a> {
+    let calendars =
+        upstream::get_calendars(url, conf.cache(), conf.env().cache_max_age).await?;
+
+    let now = Utc::now();
+    let window_start = now - conf.env().rrule_lookback;
+    let window_end = now + conf.env().rrule_lookahead;
 
     use ical::parser::ical::component::IcalCalendar;
     Ok(calendars.map(move |c: Result<IcalCalendar>| {
         let c: IcalCalendar = c?;
-        Ok(c.events
+
+        let raw_events: Vec<RawEvent> = c
+            .events
+            .into_iter()
+            .map(parse_vevent)
+            .collect::<Result<Vec<Option<RawEvent>>>>()?
             .into_iter()
-            .map(move |e| -> Result<Option<Event>> {
-                let mut uid = None;
-                let mut summary = None;
-                let mut stamp = None;
-                let mut start = None;
-                let mut end = None;
-                let mut created = None;
-                for p in e.properties.into_iter() {
-                    let mut tz = UTC;
-                    if let Some(params) = p.params {
-                        for (name, values) in params {
-                            if name == "TZID" && values.len() == 1 {
-                                use std::str::FromStr;
-                                if let Ok(current_tz) = Tz::from_str(&values[0]) {
-                                    tz = current_tz;
-                                }
-                            }
+            .flatten()
+            .collect();
+
+        let mut overrides: HashMap<String, Vec<&RawEvent>> = HashMap::new();
+        for raw in raw_events.iter().filter(|r| r.recurrence_id.is_some()) {
+            overrides.entry(raw.uid.clone()).or_default().push(raw);
+        }
+
+        let mut events = Vec::new();
+        for raw in raw_events.iter().filter(|r| r.recurrence_id.is_none()) {
+            match &raw.rrule {
+                Some(rrule) => {
+                    let start = raw.start.ok_or(Error::Inconsistency)?;
+                    let duration = raw
+                        .end
+                        .map(|end| end - start)
+                        .unwrap_or_else(chrono::Duration::zero);
+                    let occurrences = recurrence::expand(
+                        rrule,
+                        start,
+                        raw.dtstart_tz,
+                        &raw.exdates,
+                        &raw.rdates,
+                        window_start,
+                        window_end,
+                    )?;
+
+                    for occ_start in occurrences {
+                        let over = overrides.get(&raw.uid).and_then(|os| {
+                            os.iter().find(|o| o.recurrence_id == Some(occ_start))
+                        });
+
+                        // The override (when present) replaces the occurrence entirely,
+                        // so it alone decides whether the filter matches.
+                        if !over.map_or(raw, |o| *o).matches(field, filters) {
+                            continue;
                         }
-                    }
 
-                    let datetime_for_str = |s: String| -> Result<DateTime<Utc>> {
-                        use chrono::offset::TimeZone;
-                        let dt = tz.datetime_from_str(&s, "%Y%m%dT%H%M%S");
-                        if let Ok(dt) = dt {
-                            Ok(dt.with_timezone(&Utc))
-                        } else {
-                            Ok(Utc.datetime_from_str(&s, "%Y%m%dT%H%M%SZ")?)
+                        if let Some(over) = over {
+                            events.push(over.to_event());
+                            continue;
                         }
-                    };
-
-                    match p.name.as_str() {
-                        "UID" => uid = p.value,
-                        "SUMMARY" => summary = p.value,
-                        "DTSTAMP" => stamp = p.value.map(datetime_for_str),
-                        "DTSTART" => start = p.value.map(datetime_for_str),
-                        "DTEND" => end = p.value.map(datetime_for_str),
-                        "CREATED" => created = p.value.map(datetime_for_str),
-                        _ => (),
+
+                        events.push(Event {
+                            uid: format!("{}-{}", raw.uid, instant_to_icalstr(&occ_start)),
+                            summary: raw.summary.clone(),
+                            stamp: raw.stamp,
+                            start: Some(occ_start),
+                            end: Some(occ_start + duration),
+                            created: raw.created,
+                            all_day: raw.all_day,
+                            description: raw.description.clone(),
+                            location: raw.location.clone(),
+                            url: raw.url.clone(),
+                        });
                     }
                 }
-
-                if let (Some(uid), Some(summary), Some(stamp)) = (uid, summary, stamp) {
-                    if selector == summary {
-                        Ok(Some(Event {
-                            uid,
-                            summary,
-                            stamp: stamp?,
-                            start: start.transpose()?,
-                            end: end.transpose()?,
-                            created: created.transpose()?,
-                        }))
-                    } else {
-                        Ok(None)
+                None => {
+                    if raw.matches(field, filters) {
+                        events.push(raw.to_event());
                     }
-                } else {
-                    Ok(None)
                 }
-            })
-            .filter_map(|x| -> Option<Result<Event>> { x.transpose() }))
+            }
+        }
+
+        events.retain(|e| in_time_range(e, range));
+
+        Ok(events)
     }))
 }
 
-async fn collect_events(url: &str, selector: &str) -> Result<Vec<Event>> {
-    let iter = compute_events(url, selector).await?;
+async fn collect_events(
+    url: &str,
+    field: &str,
+    filters: &[Filter],
+    conf: &Conf,
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+) -> Result<Vec<Event>> {
+    let iter = compute_events(url, field, filters, conf, range).await?;
 
     let mut res = Vec::new();
     for calendar in iter {
-        for e in calendar? {
-            res.push(e?);
-        }
+        res.extend(calendar?);
     }
 
     Ok(res)
 }
 
+fn default_field() -> String {
+    "SUMMARY".to_owned()
+}
+
+/// A request with no `filter=` at all should still work — e.g. a pure
+/// time-range slice of an upstream feed — so default to the catch-all filter.
+fn default_filter() -> Vec<Filter> {
+    vec![filter::TRUE_FILTER]
+}
+
+/// A query-parameter timestamp, accepting either RFC 3339 or the iCal UTC form
+/// (`YYYYMMDDTHHMMSSZ`) used by `DTSTART`/`DTEND`.
+#[derive(Debug, Clone, Copy)]
+struct Timestamp(DateTime<Utc>);
+
+impl<'de> de::Deserialize<'de> for Timestamp {
+    fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct TimestampVisitor;
+
+        impl<'de> de::Visitor<'de> for TimestampVisitor {
+            type Value = Timestamp;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an RFC 3339 or iCal UTC timestamp")
+            }
+
+            fn visit_str<E>(self, v: &str) -> core::result::Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                if let Ok(dt) = DateTime::parse_from_rfc3339(v) {
+                    return Ok(Timestamp(dt.with_timezone(&Utc)));
+                }
+
+                use chrono::offset::TimeZone;
+                Utc.datetime_from_str(v, "%Y%m%dT%H%M%SZ")
+                    .map(Timestamp)
+                    .map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
 #[derive(Deserialize)]
 struct FilterParams {
     url: String,
-    filter: String,
+    #[serde(default = "default_field")]
+    field: String,
+    /// Repeated `filter=` query params, ANDed together. Each may itself be an
+    /// `and:`/`or:` compound filter.
+    #[serde(default = "default_filter")]
+    filter: Vec<Filter>,
+    start: Option<Timestamp>,
+    end: Option<Timestamp>,
+}
+
+impl FilterParams {
+    /// Resolves the CalDAV-style `[start, end)` time-range, treating a bare
+    /// `start` as a zero-duration point in time.
+    fn time_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let start = self.start?.0;
+        let end = self.end.map(|t| t.0).unwrap_or(start);
+        Some((start, end))
+    }
 }
 
-async fn get_json(query: Query<FilterParams>) -> Result<HttpResponse> {
-    Ok(HttpResponse::Ok().json(collect_events(&query.url, &query.filter).await?))
+async fn get_json(req: HttpRequest, conf: web::Data<Conf>) -> Result<HttpResponse> {
+    let query: FilterParams = serde_qs::from_str(req.query_string())?;
+    let range = query.time_range();
+    Ok(HttpResponse::Ok().json(
+        collect_events(&query.url, &query.field, &query.filter, &conf, range).await?,
+    ))
 }
 
-async fn get_ical(query: Query<FilterParams>) -> Result<HttpResponse> {
-    let events = collect_events(&query.url, &query.filter).await?;
+async fn get_ical(req: HttpRequest, conf: web::Data<Conf>) -> Result<HttpResponse> {
+    let query: FilterParams = serde_qs::from_str(req.query_string())?;
+    let range = query.time_range();
+    let events = collect_events(&query.url, &query.field, &query.filter, &conf, range).await?;
 
     use ics::{properties::*, *};
 
@@ -167,8 +471,30 @@ async fn get_ical(query: Query<FilterParams>) -> Result<HttpResponse> {
         .body(calendar.to_string()))
 }
 
+struct ConfInner {
+    env: EnvConfiguration,
+    cache: upstream::UpstreamCache,
+}
+
 #[derive(Clone)]
-pub struct Conf(std::sync::Arc<EnvConfiguration>);
+pub struct Conf(std::sync::Arc<ConfInner>);
+
+impl Conf {
+    fn new(env: EnvConfiguration) -> Self {
+        Conf(std::sync::Arc::new(ConfInner {
+            env,
+            cache: upstream::UpstreamCache::new(),
+        }))
+    }
+
+    fn env(&self) -> &EnvConfiguration {
+        &self.0.env
+    }
+
+    fn cache(&self) -> &upstream::UpstreamCache {
+        &self.0.cache
+    }
+}
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
@@ -176,13 +502,14 @@ async fn main() -> std::io::Result<()> {
 
     let configuration = env::get_conf().unwrap();
     let socketaddr = configuration.socketaddr;
+    let conf = Conf::new(configuration);
 
     HttpServer::new(move || {
-        let configuration = configuration.clone();
+        let conf = conf.clone();
 
         App::new()
             .wrap(Logger::default())
-            .data(configuration)
+            .data(conf)
             .service(web::resource("/v1/json").to(get_json))
             .service(web::resource("/v1/ical").to(get_ical))
     })
@@ -190,3 +517,134 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn dt(y: i32, m: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.ymd(y, m, d).and_hms(h, mi, 0)
+    }
+
+    fn event(start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) -> Event {
+        Event {
+            uid: "uid".to_owned(),
+            summary: "summary".to_owned(),
+            stamp: dt(2024, 1, 1, 0, 0),
+            created: None,
+            start,
+            end,
+            all_day: false,
+            description: None,
+            location: None,
+            url: None,
+        }
+    }
+
+    #[test]
+    fn test_in_time_range_no_range_always_matches() {
+        assert!(in_time_range(&event(None, None), None));
+        assert!(in_time_range(
+            &event(Some(dt(2024, 1, 1, 9, 0)), None),
+            None
+        ));
+    }
+
+    #[test]
+    fn test_in_time_range_requires_a_start() {
+        let range = Some((dt(2024, 1, 1, 0, 0), dt(2024, 1, 2, 0, 0)));
+        assert!(!in_time_range(&event(None, None), range));
+    }
+
+    #[test]
+    fn test_in_time_range_overlap() {
+        let range = Some((dt(2024, 1, 2, 0, 0), dt(2024, 1, 3, 0, 0)));
+
+        // Entirely before the range.
+        assert!(!in_time_range(
+            &event(Some(dt(2024, 1, 1, 0, 0)), Some(dt(2024, 1, 1, 12, 0))),
+            range
+        ));
+        // Entirely after the range.
+        assert!(!in_time_range(
+            &event(Some(dt(2024, 1, 3, 0, 0)), Some(dt(2024, 1, 3, 12, 0))),
+            range
+        ));
+        // Spans the whole range.
+        assert!(in_time_range(
+            &event(Some(dt(2024, 1, 1, 0, 0)), Some(dt(2024, 1, 4, 0, 0))),
+            range
+        ));
+    }
+
+    #[test]
+    fn test_in_time_range_missing_end_is_treated_as_a_point() {
+        let range = Some((dt(2024, 1, 2, 0, 0), dt(2024, 1, 3, 0, 0)));
+
+        // Inside the range.
+        assert!(in_time_range(&event(Some(dt(2024, 1, 2, 12, 0)), None), range));
+        // Exactly on the (exclusive) lower boundary.
+        assert!(!in_time_range(&event(Some(dt(2024, 1, 2, 0, 0)), None), range));
+        // Outside the range.
+        assert!(!in_time_range(&event(Some(dt(2024, 1, 3, 0, 0)), None), range));
+    }
+
+    fn filter_params(start: Option<Timestamp>, end: Option<Timestamp>) -> FilterParams {
+        FilterParams {
+            url: "http://example.com/feed.ics".to_owned(),
+            field: default_field(),
+            filter: default_filter(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn test_time_range_none_without_a_start() {
+        assert_eq!(filter_params(None, None).time_range(), None);
+    }
+
+    #[test]
+    fn test_time_range_bare_start_is_a_point() {
+        let start = dt(2024, 1, 1, 9, 0);
+        assert_eq!(
+            filter_params(Some(Timestamp(start)), None).time_range(),
+            Some((start, start))
+        );
+    }
+
+    #[test]
+    fn test_time_range_start_and_end() {
+        let start = dt(2024, 1, 1, 9, 0);
+        let end = dt(2024, 1, 2, 9, 0);
+        assert_eq!(
+            filter_params(Some(Timestamp(start)), Some(Timestamp(end))).time_range(),
+            Some((start, end))
+        );
+    }
+
+    #[derive(Deserialize)]
+    struct TimestampWrapper {
+        ts: Timestamp,
+    }
+
+    #[test]
+    fn test_timestamp_accepts_rfc3339() {
+        let wrapper: TimestampWrapper = serde_qs::from_str("ts=2024-01-01T09:00:00Z").unwrap();
+        assert_eq!(wrapper.ts.0, dt(2024, 1, 1, 9, 0));
+    }
+
+    #[test]
+    fn test_timestamp_accepts_ical_utc_form() {
+        let wrapper: TimestampWrapper = serde_qs::from_str("ts=20240101T090000Z").unwrap();
+        assert_eq!(wrapper.ts.0, dt(2024, 1, 1, 9, 0));
+    }
+
+    #[test]
+    fn test_timestamp_rejects_unparseable_input() {
+        let res: core::result::Result<TimestampWrapper, _> =
+            serde_qs::from_str("ts=not-a-timestamp");
+        assert!(res.is_err());
+    }
+}
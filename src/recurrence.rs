@@ -0,0 +1,193 @@
+//! Expansion of `RRULE`-carrying `VEVENT`s into concrete occurrences.
+
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use rrule::RRuleSet;
+
+/// Expands an `RRULE` (as found on a VEVENT, e.g. `FREQ=WEEKLY;BYDAY=MO`) into the
+/// occurrence start times that fall within `[window_start, window_end]` (both bounds
+/// inclusive), honouring
+/// any accompanying `EXDATE`/`RDATE` values.
+///
+/// `dtstart` is expanded in `tz`, the `DTSTART`'s original `TZID`, rather than in
+/// UTC — so e.g. a daily 9am `America/New_York` standup stays anchored at 9am
+/// local across a DST transition instead of drifting by an hour in UTC.
+pub fn expand(
+    rrule: &str,
+    dtstart: DateTime<Utc>,
+    tz: Tz,
+    exdates: &[DateTime<Utc>],
+    rdates: &[DateTime<Utc>],
+    window_start: DateTime<Utc>,
+    window_end: DateTime<Utc>,
+) -> Result<Vec<DateTime<Utc>>> {
+    let local_dtstart = dtstart.with_timezone(&tz);
+    let spec = format!(
+        "DTSTART;TZID={}:{}\nRRULE:{}",
+        tz.name(),
+        local_dtstart.format("%Y%m%dT%H%M%S"),
+        rrule
+    );
+    let mut set: RRuleSet = spec.parse().map_err(|_| Error::Inconsistency)?;
+
+    for rdate in rdates {
+        set = set.rdate(*rdate);
+    }
+    for exdate in exdates {
+        set = set.exdate(*exdate);
+    }
+
+    Ok(set.between(window_start, window_end, true))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn dt(y: i32, m: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.ymd(y, m, d).and_hms(h, mi, 0)
+    }
+
+    #[test]
+    fn test_count() {
+        let occurrences = expand(
+            "FREQ=DAILY;COUNT=3",
+            dt(2024, 1, 1, 9, 0),
+            chrono_tz::UTC,
+            &[],
+            &[],
+            dt(2024, 1, 1, 0, 0),
+            dt(2024, 2, 1, 0, 0),
+        )
+        .unwrap();
+        assert_eq!(
+            occurrences,
+            vec![
+                dt(2024, 1, 1, 9, 0),
+                dt(2024, 1, 2, 9, 0),
+                dt(2024, 1, 3, 9, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_until() {
+        let occurrences = expand(
+            "FREQ=DAILY;UNTIL=20240103T090000Z",
+            dt(2024, 1, 1, 9, 0),
+            chrono_tz::UTC,
+            &[],
+            &[],
+            dt(2024, 1, 1, 0, 0),
+            dt(2024, 2, 1, 0, 0),
+        )
+        .unwrap();
+        assert_eq!(
+            occurrences,
+            vec![
+                dt(2024, 1, 1, 9, 0),
+                dt(2024, 1, 2, 9, 0),
+                dt(2024, 1, 3, 9, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_byday() {
+        // 2024-01-01 is a Monday.
+        let occurrences = expand(
+            "FREQ=WEEKLY;BYDAY=MO;COUNT=4",
+            dt(2024, 1, 1, 9, 0),
+            chrono_tz::UTC,
+            &[],
+            &[],
+            dt(2024, 1, 1, 0, 0),
+            dt(2024, 2, 1, 0, 0),
+        )
+        .unwrap();
+        assert_eq!(
+            occurrences,
+            vec![
+                dt(2024, 1, 1, 9, 0),
+                dt(2024, 1, 8, 9, 0),
+                dt(2024, 1, 15, 9, 0),
+                dt(2024, 1, 22, 9, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exdate_excludes_occurrence() {
+        let occurrences = expand(
+            "FREQ=DAILY;COUNT=3",
+            dt(2024, 1, 1, 9, 0),
+            chrono_tz::UTC,
+            &[dt(2024, 1, 2, 9, 0)],
+            &[],
+            dt(2024, 1, 1, 0, 0),
+            dt(2024, 2, 1, 0, 0),
+        )
+        .unwrap();
+        assert_eq!(occurrences, vec![dt(2024, 1, 1, 9, 0), dt(2024, 1, 3, 9, 0)]);
+    }
+
+    #[test]
+    fn test_rdate_adds_occurrence() {
+        let occurrences = expand(
+            "FREQ=DAILY;COUNT=1",
+            dt(2024, 1, 1, 9, 0),
+            chrono_tz::UTC,
+            &[],
+            &[dt(2024, 1, 10, 9, 0)],
+            dt(2024, 1, 1, 0, 0),
+            dt(2024, 2, 1, 0, 0),
+        )
+        .unwrap();
+        assert_eq!(occurrences, vec![dt(2024, 1, 1, 9, 0), dt(2024, 1, 10, 9, 0)]);
+    }
+
+    #[test]
+    fn test_window_clips_occurrences() {
+        let occurrences = expand(
+            "FREQ=DAILY;COUNT=10",
+            dt(2024, 1, 1, 9, 0),
+            chrono_tz::UTC,
+            &[],
+            &[],
+            dt(2024, 1, 3, 0, 0),
+            dt(2024, 1, 5, 0, 0),
+        )
+        .unwrap();
+        assert_eq!(
+            occurrences,
+            vec![dt(2024, 1, 3, 9, 0), dt(2024, 1, 4, 9, 0)]
+        );
+    }
+
+    #[test]
+    fn test_dst_boundary_keeps_local_wall_clock() {
+        // US clocks sprang forward on 2024-03-10; a 9am America/New_York
+        // standup must stay at 9am local, not drift by an hour in UTC.
+        let dtstart = dt(2024, 3, 9, 14, 0); // 2024-03-09 09:00 EST (UTC-5)
+        let occurrences = expand(
+            "FREQ=DAILY;COUNT=3",
+            dtstart,
+            chrono_tz::America::New_York,
+            &[],
+            &[],
+            dt(2024, 3, 1, 0, 0),
+            dt(2024, 4, 1, 0, 0),
+        )
+        .unwrap();
+        assert_eq!(
+            occurrences,
+            vec![
+                dt(2024, 3, 9, 14, 0),  // EST, UTC-5
+                dt(2024, 3, 10, 13, 0), // EDT, UTC-4
+                dt(2024, 3, 11, 13, 0), // EDT, UTC-4
+            ]
+        );
+    }
+}
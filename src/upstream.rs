@@ -1,16 +1,261 @@
-use crate::Result;
+use crate::error::{Error, Result};
 use actix_web::client::ClientBuilder;
+use actix_web::http::StatusCode;
+use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
 use ical::parser::ical::{component::IcalCalendar, IcalParser};
+use std::collections::HashMap;
+use std::sync::Mutex;
 
-pub async fn get_calendars(url: &str) -> Result<impl Iterator<Item = Result<IcalCalendar>>> {
+/// Caps the number of distinct upstream URLs cached at once, since `url` is
+/// client-supplied and otherwise unbounded — evicting the least-recently-fetched
+/// entry keeps a client that varies `url=` from growing the cache without limit.
+const MAX_CACHE_ENTRIES: usize = 256;
+
+struct CacheEntry {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    fetched_at: DateTime<Utc>,
+    body: Bytes,
+}
+
+/// Caches the last response per upstream URL so unchanged feeds can be
+/// revalidated with a conditional GET instead of being refetched in full.
+#[derive(Default)]
+pub struct UpstreamCache(Mutex<HashMap<String, CacheEntry>>);
+
+impl UpstreamCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts `entry` for `url`, evicting the least-recently-fetched entry
+    /// first if the cache is already at [`MAX_CACHE_ENTRIES`].
+    fn insert(&self, url: String, entry: CacheEntry) {
+        let mut entries = self.0.lock().unwrap();
+        if !entries.contains_key(&url) && entries.len() >= MAX_CACHE_ENTRIES {
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, e)| e.fetched_at)
+                .map(|(url, _)| url.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(url, entry);
+    }
+}
+
+fn parse_calendars(body: Bytes) -> impl Iterator<Item = Result<IcalCalendar>> {
+    use bytes::buf::ext::BufExt;
+    let reader = std::io::BufReader::new(body.reader());
+    IcalParser::new(reader).map(|calendar| calendar.map_err(|e| e.into()))
+}
+
+/// Whether a cached entry can be served without revalidation: only applies
+/// when the upstream gave us no validators to revalidate with at all.
+fn is_fresh(entry: &CacheEntry, max_age: Duration, now: DateTime<Utc>) -> bool {
+    entry.etag.is_none() && entry.last_modified.is_none() && now - entry.fetched_at < max_age
+}
+
+/// Conditional-GET headers for revalidating a cached entry, preferring
+/// `If-None-Match` (the stronger validator) but sending both when available,
+/// as recommended by RFC 7232.
+fn conditional_headers(entry: &CacheEntry) -> Vec<(&'static str, String)> {
+    let mut headers = Vec::new();
+    if let Some(etag) = &entry.etag {
+        headers.push(("If-None-Match", etag.clone()));
+    }
+    if let Some(last_modified) = &entry.last_modified {
+        headers.push(("If-Modified-Since", last_modified.clone()));
+    }
+    headers
+}
+
+fn is_not_modified(status: StatusCode) -> bool {
+    status == StatusCode::NOT_MODIFIED
+}
+
+/// Whether a response is a success that's safe to parse and cache. A non-2xx
+/// status (e.g. a transient 500/502) must never overwrite a good cache entry.
+fn is_cacheable(status: StatusCode) -> bool {
+    status.is_success()
+}
+
+pub async fn get_calendars(
+    url: &str,
+    cache: &UpstreamCache,
+    max_age: Duration,
+) -> Result<impl Iterator<Item = Result<IcalCalendar>>> {
     let client = ClientBuilder::new()
         .no_default_headers()
         .header("User-Agent", "ical-filter")
         .finish();
 
-    use bytes::buf::ext::BufExt;
-    let buf = client.get(url).send().await?.body().await?.reader();
+    let cached = cache.0.lock().unwrap().get(url).map(|e| CacheEntry {
+        etag: e.etag.clone(),
+        last_modified: e.last_modified.clone(),
+        fetched_at: e.fetched_at,
+        body: e.body.clone(),
+    });
+
+    if let Some(cached) = &cached {
+        if is_fresh(cached, max_age, Utc::now()) {
+            return Ok(parse_calendars(cached.body.clone()));
+        }
+    }
+
+    let mut request = client.get(url);
+    if let Some(cached) = &cached {
+        for (name, value) in conditional_headers(cached) {
+            request = request.header(name, value.as_str());
+        }
+    }
+
+    let mut response = request.send().await?;
+    let status = response.status();
+
+    if is_not_modified(status) {
+        if let Some(cached) = cached {
+            return Ok(parse_calendars(cached.body));
+        }
+    }
+
+    if !is_cacheable(status) {
+        // Don't let a transient upstream failure clobber a good cache entry;
+        // serve the last known-good body if we have one instead.
+        return match cached {
+            Some(cached) => Ok(parse_calendars(cached.body)),
+            None => Err(Error::UpstreamFailure),
+        };
+    }
+
+    let etag = response
+        .headers()
+        .get("ETag")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    let last_modified = response
+        .headers()
+        .get("Last-Modified")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    let body = response.body().await?;
+
+    cache.insert(
+        url.to_owned(),
+        CacheEntry {
+            etag,
+            last_modified,
+            fetched_at: Utc::now(),
+            body: body.clone(),
+        },
+    );
+
+    Ok(parse_calendars(body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(etag: Option<&str>, last_modified: Option<&str>, fetched_at: DateTime<Utc>) -> CacheEntry {
+        CacheEntry {
+            etag: etag.map(str::to_owned),
+            last_modified: last_modified.map(str::to_owned),
+            fetched_at,
+            body: Bytes::new(),
+        }
+    }
+
+    #[test]
+    fn test_is_fresh_only_applies_without_validators() {
+        let now = Utc::now();
+        let max_age = Duration::seconds(300);
+
+        let no_validators = entry(None, None, now - Duration::seconds(60));
+        assert!(is_fresh(&no_validators, max_age, now));
+
+        let stale_no_validators = entry(None, None, now - Duration::seconds(600));
+        assert!(!is_fresh(&stale_no_validators, max_age, now));
+
+        let with_etag = entry(Some("\"abc\""), None, now - Duration::seconds(60));
+        assert!(!is_fresh(&with_etag, max_age, now));
+
+        let with_last_modified = entry(None, Some("Mon, 01 Jan 2024 00:00:00 GMT"), now - Duration::seconds(60));
+        assert!(!is_fresh(&with_last_modified, max_age, now));
+    }
+
+    #[test]
+    fn test_conditional_headers_sends_both_validators() {
+        let now = Utc::now();
+
+        let etag_only = entry(Some("\"abc\""), None, now);
+        assert_eq!(
+            conditional_headers(&etag_only),
+            vec![("If-None-Match", "\"abc\"".to_owned())]
+        );
+
+        let last_modified_only = entry(None, Some("Mon, 01 Jan 2024 00:00:00 GMT"), now);
+        assert_eq!(
+            conditional_headers(&last_modified_only),
+            vec![(
+                "If-Modified-Since",
+                "Mon, 01 Jan 2024 00:00:00 GMT".to_owned()
+            )]
+        );
+
+        let both = entry(Some("\"abc\""), Some("Mon, 01 Jan 2024 00:00:00 GMT"), now);
+        assert_eq!(
+            conditional_headers(&both),
+            vec![
+                ("If-None-Match", "\"abc\"".to_owned()),
+                (
+                    "If-Modified-Since",
+                    "Mon, 01 Jan 2024 00:00:00 GMT".to_owned()
+                ),
+            ]
+        );
+
+        let neither = entry(None, None, now);
+        assert!(conditional_headers(&neither).is_empty());
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        assert!(is_not_modified(StatusCode::NOT_MODIFIED));
+        assert!(!is_not_modified(StatusCode::OK));
+    }
+
+    #[test]
+    fn test_is_cacheable() {
+        assert!(is_cacheable(StatusCode::OK));
+        assert!(!is_cacheable(StatusCode::NOT_MODIFIED));
+        assert!(!is_cacheable(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!is_cacheable(StatusCode::NOT_FOUND));
+    }
+
+    #[test]
+    fn test_insert_evicts_oldest_when_full() {
+        let cache = UpstreamCache::new();
+        let now = Utc::now();
+        for i in 0..MAX_CACHE_ENTRIES {
+            cache.insert(
+                format!("https://example.com/{}", i),
+                entry(None, None, now - Duration::seconds((MAX_CACHE_ENTRIES - i) as i64)),
+            );
+        }
+        assert_eq!(cache.0.lock().unwrap().len(), MAX_CACHE_ENTRIES);
+        assert!(cache.0.lock().unwrap().contains_key("https://example.com/0"));
+
+        cache.insert(
+            "https://example.com/new".to_owned(),
+            entry(None, None, now),
+        );
 
-    let reader = std::io::BufReader::new(buf);
-    Ok(IcalParser::new(reader).map(|calendar| calendar.map_err(|e| e.into())))
+        let entries = cache.0.lock().unwrap();
+        assert_eq!(entries.len(), MAX_CACHE_ENTRIES);
+        assert!(!entries.contains_key("https://example.com/0"));
+        assert!(entries.contains_key("https://example.com/new"));
+    }
 }